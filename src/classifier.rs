@@ -0,0 +1,293 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+
+use crate::constants::{PeakData, COCONUT_LABELS, MAX_TEMPLATES_PER_LABEL};
+
+/// `find_peaks` returns the dominant peak plus up to 5 local maxima on
+/// either side, so this is the widest a peak list can ever be.
+pub const MAX_PEAKS: usize = 11;
+
+/// `[dominant_frequency, relative_freq_1..N, normalized_magnitude_1..N]`,
+/// zero-padded past the number of peaks in a given impulse. The frequency
+/// and magnitude halves are each L2 normalized on their own before the whole
+/// vector is L2 normalized again, so the two halves contribute comparably to
+/// the final cosine distance instead of the (far larger, raw-Hz) frequency
+/// values drowning out the harmonic/magnitude profile.
+pub const FEATURE_LEN: usize = 1 + MAX_PEAKS * 2;
+
+pub type FeatureVector = [f32; FEATURE_LEN];
+
+/// Build a normalized feature vector from a detected impulse's dominant
+/// frequency and `find_peaks` output. Returns `None` for an impulse with no
+/// peaks, since there is nothing to match against templates.
+pub fn build_feature_vector(dominant_frequency: f32, peaks: &[PeakData]) -> Option<FeatureVector> {
+	if peaks.is_empty() {
+		return None;
+	}
+
+	let n = peaks.len().min(MAX_PEAKS);
+	let magnitude_norm = peaks[..n]
+		.iter()
+		.map(|peak| peak.magnitude * peak.magnitude)
+		.sum::<f32>()
+		.sqrt();
+
+	let mut values = [0.0f32; FEATURE_LEN];
+	values[0] = dominant_frequency;
+	for (i, peak) in peaks[..n].iter().enumerate() {
+		values[1 + i] = peak.frequency - dominant_frequency;
+		values[1 + MAX_PEAKS + i] = if magnitude_norm > 0.0 {
+			peak.magnitude / magnitude_norm
+		} else {
+			0.0
+		};
+	}
+
+	normalize_slice(&mut values[0..1 + MAX_PEAKS]);
+	normalize_slice(&mut values[1 + MAX_PEAKS..]);
+
+	Some(l2_normalize(&values))
+}
+
+/// L2 normalize `slice` in place; left untouched if it's all zero.
+fn normalize_slice(slice: &mut [f32]) {
+	let norm = slice.iter().map(|v| v * v).sum::<f32>().sqrt();
+	if norm == 0.0 {
+		return;
+	}
+	for v in slice.iter_mut() {
+		*v /= norm;
+	}
+}
+
+fn l2_normalize(values: &FeatureVector) -> FeatureVector {
+	let norm = values.iter().map(|v| v * v).sum::<f32>().sqrt();
+	if norm == 0.0 {
+		return *values;
+	}
+
+	let mut normalized = [0.0f32; FEATURE_LEN];
+	for (out, v) in normalized.iter_mut().zip(values.iter()) {
+		*out = v / norm;
+	}
+	normalized
+}
+
+/// Cosine distance between two already-normalized feature vectors, i.e.
+/// `1 - dot product`: 0 for identical direction, up to 2 for opposite.
+fn cosine_distance(a: &FeatureVector, b: &FeatureVector) -> f32 {
+	let dot: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+	1.0 - dot
+}
+
+/// Labeled reference vectors the classifier matches new impulses against,
+/// one bucket per `COCONUT_LABELS` entry and capped at
+/// `MAX_TEMPLATES_PER_LABEL` so an enthusiastic training session can't grow
+/// NVS usage unbounded.
+pub struct TemplateStore {
+	templates: [Vec<FeatureVector>; COCONUT_LABELS.len()],
+}
+
+impl TemplateStore {
+	fn empty() -> Self {
+		TemplateStore {
+			templates: core::array::from_fn(|_| Vec::new()),
+		}
+	}
+
+	/// Append `vector` under `label_index`, evicting the oldest template in
+	/// that bucket once it's over `MAX_TEMPLATES_PER_LABEL`. A `label_index`
+	/// outside `COCONUT_LABELS` is ignored rather than panicking, since it
+	/// may come from a config value that wasn't validated at the edge.
+	pub fn add_template(&mut self, label_index: usize, vector: FeatureVector) {
+		let Some(bucket) = self.templates.get_mut(label_index) else {
+			warn!("Ignoring template for out-of-range label index {}", label_index);
+			return;
+		};
+		bucket.push(vector);
+		if bucket.len() > MAX_TEMPLATES_PER_LABEL {
+			bucket.remove(0);
+		}
+	}
+
+	pub fn template_count(&self, label_index: usize) -> usize {
+		self.templates[label_index].len()
+	}
+
+	/// Classify `feature` by nearest cosine distance across every stored
+	/// template, falling back to `UNKNOWN` when either no templates exist
+	/// yet or the closest one is further than `cutoff`.
+	pub fn classify(&self, feature: &FeatureVector, cutoff: f32) -> &'static str {
+		let mut best: Option<(usize, f32)> = None;
+
+		for (label_index, templates) in self.templates.iter().enumerate() {
+			for template in templates {
+				let distance = cosine_distance(feature, template);
+				let is_closer = match best {
+					Some((_, best_distance)) => distance < best_distance,
+					None => true,
+				};
+				if is_closer {
+					best = Some((label_index, distance));
+				}
+			}
+		}
+
+		match best {
+			Some((label_index, distance)) if distance <= cutoff => COCONUT_LABELS[label_index],
+			_ => "UNKNOWN",
+		}
+	}
+}
+
+const TEMPLATES_NVS_NAMESPACE: &str = "fft_templates";
+
+/// Bump whenever `build_feature_vector`'s geometry changes (e.g. how the
+/// frequency/magnitude components are scaled against each other), so
+/// templates captured under an older scheme are never compared against
+/// features built under a new one — they'd have incompatible vector
+/// directions despite being the same `FEATURE_LEN`. Changing this value
+/// orphans old keys rather than overwriting them, which is harmless since
+/// they're just a few bytes per label.
+const FEATURE_VECTOR_SCHEMA_VERSION: u8 = 2;
+
+fn label_key(label_index: usize) -> String {
+	format!("tmpl_v{}_{}", FEATURE_VECTOR_SCHEMA_VERSION, label_index)
+}
+
+const TEMPLATE_BLOB_LEN: usize = 1 + MAX_TEMPLATES_PER_LABEL * FEATURE_LEN * 4;
+
+/// Load every label's templates from NVS, falling back to an empty bucket
+/// for any label that's missing or holds a blob we don't recognise.
+pub fn load_templates(nvs_partition: EspDefaultNvsPartition) -> TemplateStore {
+	let mut store = TemplateStore::empty();
+
+	let nvs = match EspNvs::<NvsDefault>::new(nvs_partition, TEMPLATES_NVS_NAMESPACE, true) {
+		Ok(nvs) => nvs,
+		Err(e) => {
+			warn!(
+				"Failed to open `{}` NVS namespace: {:?}",
+				TEMPLATES_NVS_NAMESPACE, e
+			);
+			return store;
+		}
+	};
+
+	for (label_index, label) in COCONUT_LABELS.iter().enumerate() {
+		let mut buf = [0u8; TEMPLATE_BLOB_LEN];
+		match nvs.get_raw(&label_key(label_index), &mut buf) {
+			Ok(Some(bytes)) if bytes.len() == TEMPLATE_BLOB_LEN => {
+				let count = (bytes[0] as usize).min(MAX_TEMPLATES_PER_LABEL);
+				for i in 0..count {
+					let offset = 1 + i * FEATURE_LEN * 4;
+					let mut vector = [0.0f32; FEATURE_LEN];
+					for (j, chunk) in bytes[offset..offset + FEATURE_LEN * 4].chunks_exact(4).enumerate() {
+						vector[j] = f32::from_le_bytes(chunk.try_into().unwrap());
+					}
+					store.templates[label_index].push(vector);
+				}
+				info!("Loaded {} template(s) for `{}` from NVS", count, label);
+			}
+			Ok(Some(_)) => {
+				warn!("Stored templates for `{}` have an unexpected length, ignoring", label);
+			}
+			Ok(None) => {}
+			Err(e) => {
+				warn!("Failed to read templates for `{}` from NVS: {:?}", label, e);
+			}
+		}
+	}
+
+	store
+}
+
+/// Persist every label's templates to NVS so training survives a reboot.
+pub fn save_templates(nvs_partition: EspDefaultNvsPartition, store: &TemplateStore) -> Result<()> {
+	let mut nvs = EspNvs::<NvsDefault>::new(nvs_partition, TEMPLATES_NVS_NAMESPACE, true)?;
+
+	for (label_index, templates) in store.templates.iter().enumerate() {
+		let mut buf = [0u8; TEMPLATE_BLOB_LEN];
+		buf[0] = templates.len() as u8;
+		for (i, vector) in templates.iter().enumerate() {
+			let offset = 1 + i * FEATURE_LEN * 4;
+			for (j, v) in vector.iter().enumerate() {
+				buf[offset + j * 4..offset + j * 4 + 4].copy_from_slice(&v.to_le_bytes());
+			}
+		}
+		nvs.set_raw(&label_key(label_index), &buf)?;
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn peak(index: usize, frequency: f32, magnitude: f32) -> PeakData {
+		PeakData { index, frequency, magnitude }
+	}
+
+	#[test]
+	fn build_feature_vector_none_without_peaks() {
+		assert!(build_feature_vector(440.0, &[]).is_none());
+	}
+
+	#[test]
+	fn build_feature_vector_is_some_with_peaks() {
+		let peaks = [peak(10, 440.0, 1.0), peak(12, 460.0, 0.5)];
+		assert!(build_feature_vector(440.0, &peaks).is_some());
+	}
+
+	#[test]
+	fn add_template_evicts_oldest_past_cap() {
+		let mut store = TemplateStore::empty();
+		for i in 0..=MAX_TEMPLATES_PER_LABEL {
+			let mut vector = [0.0f32; FEATURE_LEN];
+			vector[0] = i as f32;
+			store.add_template(0, vector);
+		}
+
+		assert_eq!(store.template_count(0), MAX_TEMPLATES_PER_LABEL);
+		// The first template pushed (tagged 0.0) is the one evicted.
+		assert_eq!(store.templates[0][0][0], 1.0);
+	}
+
+	#[test]
+	fn add_template_ignores_out_of_range_label() {
+		let mut store = TemplateStore::empty();
+		store.add_template(COCONUT_LABELS.len(), [0.0f32; FEATURE_LEN]);
+
+		for label_index in 0..COCONUT_LABELS.len() {
+			assert_eq!(store.template_count(label_index), 0);
+		}
+	}
+
+	#[test]
+	fn classify_falls_back_to_unknown_without_templates() {
+		let store = TemplateStore::empty();
+		let feature = build_feature_vector(440.0, &[peak(10, 440.0, 1.0)]).unwrap();
+
+		assert_eq!(store.classify(&feature, 0.15), "UNKNOWN");
+	}
+
+	#[test]
+	fn classify_matches_nearest_template_within_cutoff() {
+		let mut store = TemplateStore::empty();
+		let feature = build_feature_vector(440.0, &[peak(10, 440.0, 1.0)]).unwrap();
+		store.add_template(1, feature);
+
+		assert_eq!(store.classify(&feature, 0.15), COCONUT_LABELS[1]);
+	}
+
+	#[test]
+	fn classify_rejects_template_further_than_cutoff() {
+		let mut store = TemplateStore::empty();
+		let template = build_feature_vector(440.0, &[peak(10, 440.0, 1.0), peak(15, 480.0, 0.9)]).unwrap();
+		store.add_template(1, template);
+
+		let feature = build_feature_vector(220.0, &[peak(20, 220.0, 1.0), peak(25, 320.0, 0.2)]).unwrap();
+		assert_eq!(store.classify(&feature, 0.0001), "UNKNOWN");
+	}
+}
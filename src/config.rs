@@ -0,0 +1,190 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+
+use crate::constants::*;
+
+const CONFIG_NVS_NAMESPACE: &str = "fft_config";
+const CONFIG_NVS_KEY: &str = "config";
+
+/// Field-tunable parameters that used to be compile-time constants in
+/// `constants.rs`. The FFT loop reads this through a shared `Arc<RwLock<_>>`
+/// so the `/config` web UI can adjust it without a reflash, and it's
+/// persisted to NVS so changes survive a reboot.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+	pub amplitude_threshold: AmplitudeThreshold,
+	/// Multiplier `k` in the spectral-flux onset rule: a new spectrum is an
+	/// onset once its flux exceeds `median(recent flux) * k`.
+	pub onset_flux_multiplier: f32,
+	pub impulse_time_threshold: u64,
+	/// Cosine-distance cutoff for the nearest-template coconut classifier.
+	pub classifier_cutoff: f32,
+	/// Index into `COCONUT_LABELS` currently selected for training-mode
+	/// captures, or `NO_TRAINING_LABEL` when training mode has no target.
+	pub training_label_index: u8,
+	/// Smoothing factor for the overlapping-window squared-magnitude EMA.
+	pub spectral_ema_alpha: f32,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Config {
+			amplitude_threshold: AMPLITUDE_THRESHOLD,
+			onset_flux_multiplier: ONSET_FLUX_MULTIPLIER,
+			impulse_time_threshold: IMPULSE_TIME_THRESHOLD,
+			classifier_cutoff: CLASSIFIER_DISTANCE_CUTOFF,
+			training_label_index: NO_TRAINING_LABEL,
+			spectral_ema_alpha: SPECTRAL_EMA_ALPHA,
+		}
+	}
+}
+
+const CONFIG_BLOB_LEN: usize = 4 * 4 + 8 + 4 + 1 + 4;
+
+impl Config {
+	fn to_bytes(self) -> [u8; CONFIG_BLOB_LEN] {
+		let mut buf = [0u8; CONFIG_BLOB_LEN];
+		let mut offset = 0;
+		let mut push = |bytes: &[u8]| {
+			buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+			offset += bytes.len();
+		};
+
+		push(&self.amplitude_threshold.frequency_cutoff.to_le_bytes());
+		push(&self.amplitude_threshold.low_freq_threshold.to_le_bytes());
+		push(&self.amplitude_threshold.high_freq_threshold.to_le_bytes());
+		push(&self.onset_flux_multiplier.to_le_bytes());
+		push(&self.impulse_time_threshold.to_le_bytes());
+		push(&self.classifier_cutoff.to_le_bytes());
+		push(&[self.training_label_index]);
+		push(&self.spectral_ema_alpha.to_le_bytes());
+
+		buf
+	}
+
+	fn from_bytes(bytes: &[u8]) -> Option<Config> {
+		if bytes.len() != CONFIG_BLOB_LEN {
+			return None;
+		}
+
+		let f32_at = |i: usize| f32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+		let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+
+		// A corrupted/stale blob (e.g. power loss mid-write, or `COCONUT_LABELS`
+		// shrinking since this was written) could hold a `training_label_index`
+		// that's neither a valid label nor the `NO_TRAINING_LABEL` sentinel;
+		// fall back to the sentinel rather than let it reach an unchecked index
+		// later.
+		let training_label_index = bytes[28];
+		let training_label_index = if training_label_index == NO_TRAINING_LABEL
+			|| (training_label_index as usize) < COCONUT_LABELS.len()
+		{
+			training_label_index
+		} else {
+			NO_TRAINING_LABEL
+		};
+
+		Some(Config {
+			amplitude_threshold: AmplitudeThreshold {
+				frequency_cutoff: f32_at(0),
+				low_freq_threshold: f32_at(4),
+				high_freq_threshold: f32_at(8),
+			},
+			onset_flux_multiplier: f32_at(12),
+			impulse_time_threshold: u64_at(16),
+			classifier_cutoff: f32_at(24),
+			training_label_index,
+			spectral_ema_alpha: f32_at(29),
+		})
+	}
+}
+
+/// Load the persisted config from NVS, falling back to the compile-time
+/// defaults when the namespace is empty or holds a blob we don't recognise.
+pub fn load_config(nvs_partition: EspDefaultNvsPartition) -> Config {
+	let nvs = match EspNvs::<NvsDefault>::new(nvs_partition, CONFIG_NVS_NAMESPACE, true) {
+		Ok(nvs) => nvs,
+		Err(e) => {
+			warn!("Failed to open `{}` NVS namespace: {:?}", CONFIG_NVS_NAMESPACE, e);
+			return Config::default();
+		}
+	};
+
+	let mut buf = [0u8; CONFIG_BLOB_LEN];
+	match nvs.get_raw(CONFIG_NVS_KEY, &mut buf) {
+		Ok(Some(bytes)) => match Config::from_bytes(bytes) {
+			Some(config) => {
+				info!("Loaded tunable config from NVS");
+				config
+			}
+			None => {
+				warn!("Stored config blob has an unexpected length, using defaults");
+				Config::default()
+			}
+		},
+		Ok(None) => {
+			info!("No persisted config found in NVS, using defaults");
+			Config::default()
+		}
+		Err(e) => {
+			warn!("Failed to read config from NVS: {:?}", e);
+			Config::default()
+		}
+	}
+}
+
+/// Persist `config` to NVS so it survives a reboot.
+pub fn save_config(nvs_partition: EspDefaultNvsPartition, config: &Config) -> Result<()> {
+	let mut nvs = EspNvs::<NvsDefault>::new(nvs_partition, CONFIG_NVS_NAMESPACE, true)?;
+	nvs.set_raw(CONFIG_NVS_KEY, &config.to_bytes())?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn round_trips_through_bytes() {
+		let config = Config {
+			amplitude_threshold: AmplitudeThreshold {
+				frequency_cutoff: 1200.0,
+				low_freq_threshold: 0.0007,
+				high_freq_threshold: 0.0009,
+			},
+			onset_flux_multiplier: 2.1,
+			impulse_time_threshold: 150,
+			classifier_cutoff: 0.2,
+			training_label_index: 1,
+			spectral_ema_alpha: 0.4,
+		};
+
+		let restored = Config::from_bytes(&config.to_bytes()).unwrap();
+
+		assert_eq!(restored.amplitude_threshold.frequency_cutoff, config.amplitude_threshold.frequency_cutoff);
+		assert_eq!(restored.amplitude_threshold.low_freq_threshold, config.amplitude_threshold.low_freq_threshold);
+		assert_eq!(restored.amplitude_threshold.high_freq_threshold, config.amplitude_threshold.high_freq_threshold);
+		assert_eq!(restored.onset_flux_multiplier, config.onset_flux_multiplier);
+		assert_eq!(restored.impulse_time_threshold, config.impulse_time_threshold);
+		assert_eq!(restored.classifier_cutoff, config.classifier_cutoff);
+		assert_eq!(restored.training_label_index, config.training_label_index);
+		assert_eq!(restored.spectral_ema_alpha, config.spectral_ema_alpha);
+	}
+
+	#[test]
+	fn from_bytes_rejects_wrong_length() {
+		assert!(Config::from_bytes(&[0u8; 3]).is_none());
+	}
+
+	#[test]
+	fn from_bytes_resets_out_of_range_training_label_to_sentinel() {
+		let config = Config {
+			training_label_index: 200,
+			..Config::default()
+		};
+
+		let restored = Config::from_bytes(&config.to_bytes()).unwrap();
+		assert_eq!(restored.training_label_index, NO_TRAINING_LABEL);
+	}
+}
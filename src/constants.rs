@@ -14,8 +14,21 @@ pub const FREQUENCY_MAGNITUDE_LENGHT: usize = FFT_LENGTH / 2;
 
 pub const FFT_LENGTH_BYTES: usize = FFT_LENGTH * 4;
 
+// 50% overlap: advance the FFT window by half its length each time instead
+// of discarding it and starting from scratch, so consecutive spectra share
+// half their samples (Welch's method).
+pub const FFT_HOP_LENGTH: usize = FFT_LENGTH / 2;
+
+pub const FFT_HOP_LENGTH_BYTES: usize = FFT_HOP_LENGTH * 4;
+
 pub const FREQ_BIN_WIDTH: f32 = SAMPLING_RATE as f32 / FFT_LENGTH as f32;
 
+// Default smoothing factor for the exponential moving average of squared
+// magnitudes across overlapping windows. Closer to 1.0 tracks new spectra
+// faster but averages less; closer to 0.0 is smoother but slower to react.
+// Tunable from the `/config` web UI.
+pub const SPECTRAL_EMA_ALPHA: f32 = 0.3;
+
 // Tune this value to remove noise of low amplitude from signal
 pub const AMPLITUDE_THRESHOLD: AmplitudeThreshold = AmplitudeThreshold {
 	frequency_cutoff: 1000.0,    // amplitude threshold boundary
@@ -23,15 +36,63 @@ pub const AMPLITUDE_THRESHOLD: AmplitudeThreshold = AmplitudeThreshold {
 	high_freq_threshold: 0.0005, // For ≥frequency_cutoff (keep between 0.05 to 0.005)
 };
 
-pub const IMPULSE_THRESHOLD: f32 = 0.0012;
+// Default multiplier `k` in the spectral-flux onset rule: an onset fires
+// when flux exceeds `median(recent flux) * k`. Tunable from the `/config`
+// web UI.
+pub const ONSET_FLUX_MULTIPLIER: f32 = 1.5;
+
+// Number of recent per-frame flux values kept to estimate the "normal"
+// flux level an onset must stand out from.
+pub const ONSET_FLUX_WINDOW_SIZE: usize = 20;
+
 pub const IMPULSE_TIME_THRESHOLD: u64 = 100; // ms
 
+// Labels the nearest-template classifier can be trained on, and that show up
+// as options in the `/config` training-label selector.
+pub const COCONUT_LABELS: [&str; 3] = ["BROWN COCONUT", "FLESHY COCONUT", "WATER COCONUT"];
+
+// Cosine-distance cutoff beyond which an impulse doesn't match any stored
+// template and is classified as UNKNOWN. This is the default loaded into
+// `Config` when NVS has nothing persisted yet; from then on it's tunable
+// from the `/config` web UI.
+pub const CLASSIFIER_DISTANCE_CUTOFF: f32 = 0.15;
+
+// Sentinel `training_label_index` meaning "no label selected, training mode
+// is a no-op". Kept as a plain index rather than `Option<u8>` so `Config`
+// stays `Copy`.
+pub const NO_TRAINING_LABEL: u8 = 255;
+
+// Reference vectors kept per label before the oldest is evicted.
+pub const MAX_TEMPLATES_PER_LABEL: usize = 8;
+
+// How long the button must be held down to toggle training mode instead of
+// recording.
+pub const TRAINING_LONG_PRESS_MS: u64 = 1500;
+
 pub static WIFI_SSID: &str = "ESP32-FFT-Analyzer";
 
 pub const WIFI_PASSWORD: &str = "spectrum123";
 
+// Credentials for the home/lab network the analyzer should try to join before
+// falling back to its own access point. Leave STA_SSID empty to skip station
+// mode entirely and boot straight into AP mode.
+pub static STA_SSID: &str = "";
+
+pub const STA_PASSWORD: &str = "";
+
+// Number of consecutive failed station-mode connection attempts before the
+// link-state machine gives up and starts the fallback access point.
+pub const WIFI_STA_MAX_RETRIES: u8 = 5;
+
+// Base backoff between station reconnect attempts; doubles on each failure
+// up to `WIFI_STA_MAX_BACKOFF_MS`.
+pub const WIFI_STA_BASE_BACKOFF_MS: u64 = 500;
+
+pub const WIFI_STA_MAX_BACKOFF_MS: u64 = 8000;
+
 pub const AUDIO_SAMPLE_DELTA: u64 = 2; // in milliseconds, for sps divide APS by 1000
 
+#[derive(Debug, Clone, Copy)]
 pub struct AmplitudeThreshold {
 	pub frequency_cutoff: f32,
 	pub low_freq_threshold: f32,
@@ -1,8 +1,12 @@
+mod classifier;
+mod config;
 mod constants;
 mod display;
 mod web_server;
 
 use anyhow::Result;
+use classifier::{build_feature_vector, load_templates, save_templates, TemplateStore};
+use config::{load_config, Config as TunableConfig};
 use constants::*;
 use display::spawn_display_thread;
 use esp_idf_svc::{
@@ -18,9 +22,11 @@ use esp_idf_svc::{
 		peripherals::Peripherals,
 	},
 	log::EspLogger,
+	nvs::EspDefaultNvsPartition,
 };
-use log::{error, info};
+use log::{error, info, warn};
 use rustfft::{num_complex::Complex, FftPlanner};
+use std::collections::VecDeque;
 use std::sync::{Arc, RwLock};
 use web_server::*;
 
@@ -61,6 +67,8 @@ fn main() -> Result<()> {
 
 	let mut previous_button_state = Level::High; // Start with button not pressed
 	let mut recording_enabled = false; // Toggle state for recording
+	let mut button_press_started_ms: Option<u64> = None;
+	let mut long_press_handled = false;
 
 	let system_state: Arc<RwLock<Arc<FFTData>>> = Arc::new(RwLock::new(Arc::new(FFTData {
 		magnitudes: [0.0; FREQUENCY_MAGNITUDE_LENGHT],
@@ -69,9 +77,25 @@ fn main() -> Result<()> {
 		latest_impulse: None,
 	})));
 
+	// Load the tunable thresholds from NVS (falling back to the constants.rs
+	// defaults), then share them so the `/config` web UI can update them
+	// live without a reflash.
+	let nvs_partition = EspDefaultNvsPartition::take()?;
+	let tunable_config: Arc<RwLock<TunableConfig>> =
+		Arc::new(RwLock::new(load_config(nvs_partition.clone())));
+
+	// Reference vectors for the nearest-template coconut classifier, along
+	// with the NVS handle used to persist new templates captured in
+	// training mode. Owned by the FFT loop alone; the web UI only selects
+	// which label training mode captures into via `tunable_config`.
+	let templates_nvs_partition = nvs_partition.clone();
+	let mut template_store: TemplateStore = load_templates(templates_nvs_partition.clone());
+	let mut training_mode_enabled = false;
+
 	// Clone state for the Wi-Fi/server thread
 	let server_state = system_state.clone();
 	let display_state = system_state.clone();
+	let server_tunable_config = tunable_config.clone();
 
 	// Configure and initialize the I2S driver
 	let clock_config = StdClkConfig::from_sample_rate_hz(SAMPLING_RATE);
@@ -109,57 +133,126 @@ fn main() -> Result<()> {
 		esp_idf_svc::hal::cpu::core()
 	);
 
-	spawn_wifi_thread(modem, server_state).expect("Failed to spawn WiFi/server thread!");
+	spawn_wifi_thread(modem, server_state, server_tunable_config, nvs_partition)
+		.expect("Failed to spawn WiFi/server thread!");
 	spawn_display_thread(display_state, i2c0, sda_pin, scl_pin);
 
 	let mut buffer: Vec<u8> = vec![0; FREQUENCY_MAGNITUDE_LENGHT];
-	let mut accumulated_buffer: Vec<u8> = vec![0; FFT_LENGTH_BYTES];
-	let mut acc_index = 0;
 	let timeout = AUDIO_SAMPLE_DELTA as u32;
 
+	// Ring buffer holding the most recent `FFT_LENGTH` samples. The FFT
+	// window advances by `FFT_HOP_LENGTH` (50%) each transform instead of
+	// being discarded and rebuilt from scratch, so consecutive spectra
+	// overlap (Welch's method).
+	let mut ring_buffer: Vec<u8> = vec![0; FFT_LENGTH_BYTES];
+	let mut ring_write_pos: usize = 0;
+	let mut ring_filled_bytes: usize = 0;
+	let mut hop_accumulated_bytes: usize = 0;
+
+	// Exponential moving average of the squared per-bin magnitude across
+	// overlapping windows, and whether it's seen a first spectrum yet (the
+	// first one seeds it directly rather than blending against zeroes).
+	let mut ema_magnitudes_sq: [f32; FREQUENCY_MAGNITUDE_LENGHT] = [0.0; FREQUENCY_MAGNITUDE_LENGHT];
+	let mut ema_warm = false;
+
+	// Spectral-flux onset detection state: the previous spectrum (to diff
+	// against), a sliding window of recent flux values (to estimate the
+	// "normal" flux level an onset must stand out from), and the last time
+	// an onset fired (for the debounce window).
+	let mut prev_magnitudes: [f32; FREQUENCY_MAGNITUDE_LENGHT] = [0.0; FREQUENCY_MAGNITUDE_LENGHT];
+	let mut prev_magnitudes_warm = false;
+	let mut flux_window: VecDeque<f32> = VecDeque::with_capacity(ONSET_FLUX_WINDOW_SIZE);
+	let mut last_onset_time_ms: u64 = 0;
+
 	// Main FFT loop
 	loop {
 		let current_button_state = button.get_level();
-
-		// Detect button press (transition from High to Low)
-		if previous_button_state == Level::High && current_button_state == Level::Low {
-			// Button was just pressed - toggle recording state
-			recording_enabled = !recording_enabled;
-
-			if recording_enabled {
-				info!("Button pressed: Starting FFT recording");
-			} else {
-				info!("Button pressed: Stopping FFT recording");
-				// Reset buffer when stopping recording
-				acc_index = 0;
+		let button_now_ms: u64 = unsafe { esp_idf_svc::sys::esp_timer_get_time() as u64 / 1000 };
+
+		match (previous_button_state, current_button_state) {
+			(Level::High, Level::Low) => {
+				// Button just pressed; don't act yet since we don't know if
+				// this will turn out to be a short or a long press.
+				button_press_started_ms = Some(button_now_ms);
+				long_press_handled = false;
+			}
+			(Level::Low, Level::Low) => {
+				// Still held - toggle training mode as soon as the long-press
+				// threshold is crossed, instead of waiting for release.
+				if !long_press_handled {
+					if let Some(started_ms) = button_press_started_ms {
+						if button_now_ms - started_ms >= TRAINING_LONG_PRESS_MS {
+							long_press_handled = true;
+							training_mode_enabled = !training_mode_enabled;
+							info!(
+								"Long press: training mode {}",
+								if training_mode_enabled { "ENABLED" } else { "DISABLED" }
+							);
+						}
+					}
+				}
 			}
+			(Level::Low, Level::High) => {
+				// Button released. A long press already toggled training mode
+				// above, so only a short press toggles recording here.
+				if !long_press_handled {
+					recording_enabled = !recording_enabled;
+
+					if recording_enabled {
+						info!("Button pressed: Starting FFT recording");
+					} else {
+						info!("Button pressed: Stopping FFT recording");
+					}
+
+					// Reset the overlap buffer and spectral EMA whenever
+					// recording toggles, so a new session doesn't pick up
+					// stale samples or average against an old spectrum.
+					ring_write_pos = 0;
+					ring_filled_bytes = 0;
+					hop_accumulated_bytes = 0;
+					ema_warm = false;
+					prev_magnitudes_warm = false;
+					flux_window.clear();
+
+					// Update shared state with new recording status
+					let current_state = {
+						let read_guard = system_state.read().unwrap();
+						read_guard.clone()
+					};
+
+					*system_state.write().unwrap() = Arc::new(FFTData {
+						magnitudes: current_state.magnitudes,
+						dominant_frequency: current_state.dominant_frequency,
+						is_recording: recording_enabled,
+						latest_impulse: None,
+					});
+				}
 
-			// Update shared state with new recording status
-			let current_state = {
-				let read_guard = system_state.read().unwrap();
-				read_guard.clone()
-			};
-
-			*system_state.write().unwrap() = Arc::new(FFTData {
-				magnitudes: current_state.magnitudes,
-				dominant_frequency: current_state.dominant_frequency,
-				is_recording: recording_enabled,
-				latest_impulse: None,
-			});
+				button_press_started_ms = None;
+			}
+			_ => {}
 		}
 
 		// Save current state for next comparison
 		previous_button_state = current_button_state;
 
 		if recording_enabled {
-			while acc_index < FFT_LENGTH_BYTES {
+			// Keep reading until the ring is full for the first time, and
+			// from then on until at least one hop's worth of new samples has
+			// landed since the last processed window.
+			while ring_filled_bytes < FFT_LENGTH_BYTES || hop_accumulated_bytes < FFT_HOP_LENGTH_BYTES {
 				match i2s.read(&mut buffer, timeout) {
 					Ok(bytes_read) => {
-						let space_left = FFT_LENGTH_BYTES - acc_index;
-						let bytes_to_copy = bytes_read.min(space_left);
-						accumulated_buffer[acc_index..acc_index + bytes_to_copy]
-							.copy_from_slice(&buffer[..bytes_to_copy]);
-						acc_index += bytes_to_copy;
+						write_into_ring_buffer(&mut ring_buffer, &mut ring_write_pos, &buffer[..bytes_read]);
+						ring_filled_bytes = (ring_filled_bytes + bytes_read).min(FFT_LENGTH_BYTES);
+						// Capped the same way `ring_filled_bytes` is: during
+						// the initial fill this can otherwise "bank" a full
+						// second hop's worth of backlog (since the ring
+						// needs two hops before it's full), which would
+						// immediately satisfy the hop threshold again next
+						// pass with zero new samples read and reprocess the
+						// same window twice.
+						hop_accumulated_bytes = (hop_accumulated_bytes + bytes_read).min(FFT_HOP_LENGTH_BYTES);
 					}
 					Err(e) => {
 						error!("I2S read error: {:?}", e);
@@ -168,8 +261,18 @@ fn main() -> Result<()> {
 				}
 			}
 
-			if acc_index >= FFT_LENGTH_BYTES {
-				// Process accumulated buffer for FFT
+			if hop_accumulated_bytes >= FFT_HOP_LENGTH_BYTES && ring_filled_bytes >= FFT_LENGTH_BYTES {
+				hop_accumulated_bytes -= FFT_HOP_LENGTH_BYTES;
+
+				// Snapshot the live config once per block; it may be updated
+				// concurrently from the `/config` web UI.
+				let current_config = *tunable_config.read().unwrap();
+
+				// Linearize the ring buffer into chronological order (oldest
+				// sample first) so the Hann window applies the same way it
+				// always has.
+				let windowed_buffer = linearize_ring_buffer(&ring_buffer, ring_write_pos);
+
 				let mut samples: Vec<Complex<f32>> =
 					vec![Complex::<f32>::new(0.0, 0.0); FFT_LENGTH];
 
@@ -177,10 +280,10 @@ fn main() -> Result<()> {
 				for i in 0..FFT_LENGTH {
 					let offset = i * 4;
 					let val = i32::from_le_bytes([
-						accumulated_buffer[offset],
-						accumulated_buffer[offset + 1],
-						accumulated_buffer[offset + 2],
-						accumulated_buffer[offset + 3],
+						windowed_buffer[offset],
+						windowed_buffer[offset + 1],
+						windowed_buffer[offset + 2],
+						windowed_buffer[offset + 3],
 					]);
 					let sample_f32 = (val as f32) / 2147483648.0 * hann_window[i];
 					samples[i] = Complex::new(sample_f32, 0.0);
@@ -189,20 +292,35 @@ fn main() -> Result<()> {
 				// Perform FFT
 				fft.process(&mut samples);
 
-				// Calculate magnitudes and find dominant frequency
+				// Blend this window's squared magnitude into the running
+				// average (Welch's method), then derive magnitudes and find
+				// the dominant frequency from the smoothed spectrum.
 				let scale_factor: f32 = 1.0 / FFT_LENGTH as f32;
+
+				for i in 0..FREQUENCY_MAGNITUDE_LENGHT {
+					let mag = samples[i].norm() * scale_factor;
+					let mag_sq = mag * mag;
+					ema_magnitudes_sq[i] = if ema_warm {
+						current_config.spectral_ema_alpha * mag_sq
+							+ (1.0 - current_config.spectral_ema_alpha) * ema_magnitudes_sq[i]
+					} else {
+						mag_sq
+					};
+				}
+				ema_warm = true;
+
 				let mut magnitudes: [f32; FREQUENCY_MAGNITUDE_LENGHT] =
 					[0.0; FREQUENCY_MAGNITUDE_LENGHT];
 				let mut max_mag: f32 = 0.0;
 				let mut max_index = 0;
 
 				for i in 0..FREQUENCY_MAGNITUDE_LENGHT {
-					magnitudes[i] = samples[i].norm() * scale_factor; // Magnitude from FFT
+					magnitudes[i] = ema_magnitudes_sq[i].sqrt();
 					let frequency = i as f32 * FREQ_BIN_WIDTH;
-					let threshold = if frequency < AMPLITUDE_THRESHOLD.frequency_cutoff {
-						AMPLITUDE_THRESHOLD.low_freq_threshold
+					let threshold = if frequency < current_config.amplitude_threshold.frequency_cutoff {
+						current_config.amplitude_threshold.low_freq_threshold
 					} else {
-						AMPLITUDE_THRESHOLD.high_freq_threshold
+						current_config.amplitude_threshold.high_freq_threshold
 					};
 					if magnitudes[i] > threshold && magnitudes[i] > max_mag {
 						max_mag = magnitudes[i];
@@ -230,35 +348,49 @@ fn main() -> Result<()> {
 					0.0
 				};
 
-				// Impulse detection logic
-				static mut PREV_MAGNITUDE_SUM: f32 = 0.0;
-				static mut LAST_IMPULSE_TIME: u64 = 0;
-
-				let magnitude_sum: f32 = magnitudes.iter().sum();
-				let now: u64 = unsafe { esp_idf_svc::sys::esp_timer_get_time() as u64 / 1000 }; // Convert to ms
-				let delta = (magnitude_sum - unsafe { PREV_MAGNITUDE_SUM }).abs();
+				// Impulse detection logic: spectral flux, i.e. the half-wave
+				// rectified (positive-only) sum of the change in each bin's
+				// magnitude since the previous spectrum. Onsets show up as a
+				// spike in flux against the recent background level, so we
+				// compare it against a median over a trailing window rather
+				// than a fixed threshold.
+				let flux: f32 = if prev_magnitudes_warm {
+					magnitudes
+						.iter()
+						.zip(prev_magnitudes.iter())
+						.map(|(&mag, &prev_mag)| (mag - prev_mag).max(0.0))
+						.sum()
+				} else {
+					0.0
+				};
+				prev_magnitudes = magnitudes;
+				prev_magnitudes_warm = true;
 
-				unsafe {
-					PREV_MAGNITUDE_SUM = magnitude_sum;
+				if flux_window.len() >= ONSET_FLUX_WINDOW_SIZE {
+					flux_window.pop_front();
 				}
+				flux_window.push_back(flux);
+
+				let now: u64 = unsafe { esp_idf_svc::sys::esp_timer_get_time() as u64 / 1000 }; // Convert to ms
 
 				let mut detected_impulse = None;
 
-				// Check if this is an impulse (sudden change in magnitude)
-				if delta > IMPULSE_THRESHOLD
-					&& now - unsafe { LAST_IMPULSE_TIME } > IMPULSE_TIME_THRESHOLD
-					&& frequency > 100.0
+				// Check if this is an onset (flux spikes above the recent
+				// background level).
+				let is_onset = median(&flux_window)
+					.map(|background| flux > background * current_config.onset_flux_multiplier)
+					.unwrap_or(false)
+					&& now - last_onset_time_ms > current_config.impulse_time_threshold
+					&& frequency > 100.0;
 				// No impulse below 100hz is required as the coconut is giving impulse at and above 100Hz.
-				{
-					unsafe {
-						LAST_IMPULSE_TIME = now;
-					}
+				if is_onset {
+					last_onset_time_ms = now;
 
 					// Find additional peaks around dominant frequency
 					let peak_indices = find_peaks(&magnitudes, max_index);
 
 					// Create peak data
-					let peaks = peak_indices
+					let peaks: Vec<PeakData> = peak_indices
 						.iter()
 						.map(|&idx| PeakData {
 							index: idx,
@@ -272,17 +404,38 @@ fn main() -> Result<()> {
 					// 	now, frequency
 					// );
 
-					// Classify coconut type based on dominant frequency
-					let coconut_type = if (1900.0..=2800.0).contains(&frequency) {
-						"BROWN COCONUT"
-					} else if (700.0..=899.0).contains(&frequency) {
-						"FLESHY COCONUT"
-					} else if (900.0..=1700.0).contains(&frequency) {
-						"WATER COCONUT"
-					} else {
-						"UNKNOWN"
+					// Classify via nearest-template cosine distance over the
+					// dominant frequency plus the relative frequencies and
+					// normalized magnitudes of the surrounding peaks.
+					let feature = build_feature_vector(frequency, &peaks);
+					let coconut_type = match &feature {
+						Some(vector) => template_store.classify(vector, current_config.classifier_cutoff),
+						None => "UNKNOWN",
 					};
 
+					// Training mode: append this impulse's feature vector to
+					// the currently selected label in addition to classifying
+					// it, so the device can be calibrated for a
+					// user's own coconuts without a reflash.
+					let training_label_index = current_config.training_label_index;
+					if training_mode_enabled
+						&& training_label_index != NO_TRAINING_LABEL
+						&& (training_label_index as usize) < COCONUT_LABELS.len()
+					{
+						if let Some(vector) = feature {
+							let label_index = training_label_index as usize;
+							template_store.add_template(label_index, vector);
+							info!(
+								"Training: captured template for `{}` ({} stored)",
+								COCONUT_LABELS[label_index],
+								template_store.template_count(label_index)
+							);
+							if let Err(e) = save_templates(templates_nvs_partition.clone(), &template_store) {
+								warn!("Failed to persist templates to NVS: {:?}", e);
+							}
+						}
+					}
+
 					// Log the coconut type if it's identified
 					// if coconut_type != "UNKNOWN" {
 					// 	info!(
@@ -312,8 +465,6 @@ fn main() -> Result<()> {
 
 				// Update shared state with new FFT data
 				*system_state.write().unwrap() = updated_fft_data;
-
-				acc_index = 0;
 			}
 		} else {
 			// Small delay to avoid busy-waiting when not recording
@@ -322,6 +473,52 @@ fn main() -> Result<()> {
 	}
 }
 
+/// Copy `data` into `ring` at `write_pos`, wrapping around the end, and
+/// advance `write_pos` past what was written.
+fn write_into_ring_buffer(ring: &mut [u8], write_pos: &mut usize, data: &[u8]) {
+	let len = ring.len();
+	let mut pos = *write_pos;
+	let mut remaining = data;
+
+	while !remaining.is_empty() {
+		let space = len - pos;
+		let take = remaining.len().min(space);
+		ring[pos..pos + take].copy_from_slice(&remaining[..take]);
+		pos = (pos + take) % len;
+		remaining = &remaining[take..];
+	}
+
+	*write_pos = pos;
+}
+
+/// Read a full ring buffer out in chronological (oldest-first) order. Once
+/// the ring is full, `write_pos` points at the oldest byte (the next one
+/// due to be overwritten), so that's where the linear copy starts.
+fn linearize_ring_buffer(ring: &[u8], write_pos: usize) -> Vec<u8> {
+	let mut out = Vec::with_capacity(ring.len());
+	out.extend_from_slice(&ring[write_pos..]);
+	out.extend_from_slice(&ring[..write_pos]);
+	out
+}
+
+/// Median of a small window of recent flux values, used as the "normal"
+/// flux level an onset must stand out from. `None` while the window is
+/// still empty.
+fn median(values: &VecDeque<f32>) -> Option<f32> {
+	if values.is_empty() {
+		return None;
+	}
+
+	let mut sorted: Vec<f32> = values.iter().copied().collect();
+	sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+	let mid = sorted.len() / 2;
+	Some(if sorted.len() % 2 == 0 {
+		(sorted[mid - 1] + sorted[mid]) / 2.0
+	} else {
+		sorted[mid]
+	})
+}
+
 fn find_peaks(magnitudes: &[f32; FREQUENCY_MAGNITUDE_LENGHT], dominant_index: usize) -> Vec<usize> {
 	let mut result = vec![dominant_index];
 	let range = 5; // 5 peaks before and after
@@ -368,3 +565,52 @@ fn find_peaks(magnitudes: &[f32; FREQUENCY_MAGNITUDE_LENGHT], dominant_index: us
 	result.sort();
 	result
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn write_into_ring_buffer_wraps_around() {
+		let mut ring = [0u8; 4];
+		let mut write_pos = 0;
+
+		write_into_ring_buffer(&mut ring, &mut write_pos, &[1, 2, 3]);
+		assert_eq!(ring, [1, 2, 3, 0]);
+		assert_eq!(write_pos, 3);
+
+		write_into_ring_buffer(&mut ring, &mut write_pos, &[4, 5, 6]);
+		assert_eq!(ring, [5, 6, 3, 4]);
+		assert_eq!(write_pos, 2);
+	}
+
+	#[test]
+	fn linearize_ring_buffer_starts_at_write_pos() {
+		// write_pos points at the oldest byte once the ring has wrapped.
+		let ring = [5, 6, 3, 4];
+		assert_eq!(linearize_ring_buffer(&ring, 2), vec![3, 4, 5, 6]);
+	}
+
+	#[test]
+	fn linearize_ring_buffer_not_yet_wrapped() {
+		let ring = [1, 2, 3, 0];
+		assert_eq!(linearize_ring_buffer(&ring, 0), vec![1, 2, 3, 0]);
+	}
+
+	#[test]
+	fn median_of_empty_window_is_none() {
+		assert_eq!(median(&VecDeque::new()), None);
+	}
+
+	#[test]
+	fn median_of_odd_length_window() {
+		let window: VecDeque<f32> = [3.0, 1.0, 2.0].into_iter().collect();
+		assert_eq!(median(&window), Some(2.0));
+	}
+
+	#[test]
+	fn median_of_even_length_window() {
+		let window: VecDeque<f32> = [1.0, 2.0, 3.0, 4.0].into_iter().collect();
+		assert_eq!(median(&window), Some(2.5));
+	}
+}
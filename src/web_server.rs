@@ -6,20 +6,110 @@ use esp_idf_svc::{
 	hal::modem::Modem,
 	http::server::{ws::EspHttpWsConnection, Configuration, EspHttpServer},
 	io::EspIOError,
+	ipv4::IpEvent,
 	nvs::EspDefaultNvsPartition,
-	wifi::{AccessPointConfiguration, BlockingWifi, EspWifi},
+	wifi::{
+		AccessPointConfiguration, AuthMethod, BlockingWifi, ClientConfiguration,
+		Configuration as WifiConfiguration, EspWifi, WifiEvent,
+	},
 	ws::FrameType,
 };
+use esp_idf_svc::io::Read as _;
 use log::*;
 use std::{
-	sync::{Arc, RwLock},
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc, RwLock,
+	},
 	thread,
 	time::Duration,
 };
 
+use crate::config::{save_config, Config as TunableConfig};
 use crate::constants::*;
 use crate::FFTData;
 
+/// Station-mode link state, modelled as an explicit state machine (idle ->
+/// connecting -> connected, with disconnects dropping back to lost) instead
+/// of inferring connectivity from scattered booleans. Updated both by the
+/// connect/retry path and by `EspSystemEventLoop` WiFi/IP event
+/// subscriptions; the WiFi thread checks it once a second.
+///
+/// `Lost` is transient and worth retrying from; `ApFallback` is terminal —
+/// it means station mode has exhausted its retries and the radio has been
+/// reconfigured as AP-only, so there is no station link left to reconnect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WifiLinkState {
+	Idle,
+	Connecting,
+	Connected,
+	Lost,
+	ApFallback,
+}
+
+/// Radio power-save mode, borrowed from the `PowerManagementMode` concept in
+/// the cyw43 control layer: `NoPowerSave` keeps the radio fully awake for
+/// lowest latency, `MinModem` sleeps between DTIM beacon intervals, and
+/// `MaxModem` sleeps as aggressively as the driver allows at the cost of
+/// higher latency and jitter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PowerSaveMode {
+	NoPowerSave,
+	MinModem,
+	MaxModem,
+}
+
+/// Number of active WebSocket clients, tracked so the power-management loop
+/// can tell whether the radio needs to stay at full performance.
+static WS_CLIENT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Upper bound on how much of a `/config` POST body gets buffered. The form
+/// only ever posts a handful of short numeric fields, so a few KB is
+/// generous headroom without letting an oversized request exhaust RAM.
+const MAX_CONFIG_BODY_BYTES: usize = 2048;
+
+fn set_power_save_mode(mode: PowerSaveMode) {
+	let ps_type = match mode {
+		PowerSaveMode::NoPowerSave => esp_idf_svc::sys::wifi_ps_type_t_WIFI_PS_NONE,
+		PowerSaveMode::MinModem => esp_idf_svc::sys::wifi_ps_type_t_WIFI_PS_MIN_MODEM,
+		PowerSaveMode::MaxModem => esp_idf_svc::sys::wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+	};
+
+	match unsafe { esp_idf_svc::sys::esp_wifi_set_ps(ps_type) } {
+		0 => debug!("WiFi power-save mode set to {:?}", mode),
+		e => warn!("Failed to set WiFi power-save mode to {:?}: {}", mode, e),
+	}
+}
+
+/// Pick the power-save tier for the current activity level: full performance
+/// while recording (the FFT loop is pushing fresh data and latency matters),
+/// a lighter sleep while idle but still watched by a WebSocket client, and
+/// the deepest sleep once nothing is going on at all.
+fn idle_power_save_mode(is_recording: bool, has_client: bool) -> PowerSaveMode {
+	if is_recording {
+		PowerSaveMode::NoPowerSave
+	} else if has_client {
+		PowerSaveMode::MinModem
+	} else {
+		PowerSaveMode::MaxModem
+	}
+}
+
+/// Track activity and step the radio's power-save mode accordingly. The
+/// analyzer is battery-plausible hardware and the radio dominates idle
+/// current draw, so this runs continuously from the WiFi thread rather than
+/// being a one-shot setting at boot.
+fn apply_idle_power_save(server_state: &Arc<RwLock<Arc<FFTData>>>, current_mode: &mut PowerSaveMode) {
+	let is_recording = server_state.read().unwrap().is_recording;
+	let has_client = WS_CLIENT_COUNT.load(Ordering::Relaxed) > 0;
+	let desired_mode = idle_power_save_mode(is_recording, has_client);
+
+	if desired_mode != *current_mode {
+		set_power_save_mode(desired_mode);
+		*current_mode = desired_mode;
+	}
+}
+
 pub fn init_wifi_module() -> Result<(EspDefaultNvsPartition, EspSystemEventLoop)> {
 	let nvs = EspDefaultNvsPartition::take()?;
 
@@ -30,6 +120,15 @@ pub fn init_wifi_module() -> Result<(EspDefaultNvsPartition, EspSystemEventLoop)
 	Ok((nvs, sysloop))
 }
 
+fn ap_configuration() -> AccessPointConfiguration {
+	AccessPointConfiguration {
+		ssid: WIFI_SSID.try_into().unwrap(),
+		password: WIFI_PASSWORD.try_into().unwrap(),
+		auth_method: AuthMethod::WPA2Personal,
+		..Default::default()
+	}
+}
+
 pub fn init_wifi_ap(modem: Modem) -> Result<BlockingWifi<EspWifi<'static>>> {
 	let (nvs, sysloop) = init_wifi_module()?;
 
@@ -39,15 +138,8 @@ pub fn init_wifi_ap(modem: Modem) -> Result<BlockingWifi<EspWifi<'static>>> {
 	)
 	.expect("Failed to initialise WiFi");
 
-	wifi.set_configuration(&esp_idf_svc::wifi::Configuration::AccessPoint(
-		AccessPointConfiguration {
-			ssid: WIFI_SSID.try_into().unwrap(),
-			password: WIFI_PASSWORD.try_into().unwrap(),
-			auth_method: esp_idf_svc::wifi::AuthMethod::WPA2Personal,
-			..Default::default()
-		},
-	))
-	.expect("Failed to set WiFi configuration");
+	wifi.set_configuration(&WifiConfiguration::AccessPoint(ap_configuration()))
+		.expect("Failed to set WiFi configuration");
 
 	wifi.start().expect("Failed to start WiFi");
 	info!(
@@ -58,7 +150,250 @@ pub fn init_wifi_ap(modem: Modem) -> Result<BlockingWifi<EspWifi<'static>>> {
 	Ok(wifi)
 }
 
-pub fn init_http_server(server_state: Arc<RwLock<Arc<FFTData>>>) -> Result<EspHttpServer<'static>> {
+/// Attempt to join `STA_SSID`, retrying with exponential backoff up to
+/// `WIFI_STA_MAX_RETRIES` times. Returns `true` once the link reaches
+/// `Connected`.
+fn connect_station_with_retry(
+	wifi: &mut BlockingWifi<EspWifi<'static>>,
+	link_state: &Arc<RwLock<WifiLinkState>>,
+) -> bool {
+	for attempt in 0..WIFI_STA_MAX_RETRIES {
+		*link_state.write().unwrap() = WifiLinkState::Connecting;
+		info!(
+			"Connecting to `{}` (attempt {}/{})...",
+			STA_SSID,
+			attempt + 1,
+			WIFI_STA_MAX_RETRIES
+		);
+
+		if let Err(e) = wifi.connect() {
+			warn!("WiFi connect() failed: {:?}", e);
+		} else if wifi.wait_netif_up().is_ok() {
+			*link_state.write().unwrap() = WifiLinkState::Connected;
+			return true;
+		}
+
+		*link_state.write().unwrap() = WifiLinkState::Lost;
+
+		let backoff = (WIFI_STA_BASE_BACKOFF_MS * 2u64.pow(attempt as u32)).min(WIFI_STA_MAX_BACKOFF_MS);
+		thread::sleep(Duration::from_millis(backoff));
+	}
+
+	false
+}
+
+/// Bring the radio up and keep it up for the lifetime of the WiFi thread.
+///
+/// If `STA_SSID` is configured, the radio starts in `Mixed` (APSTA) mode so
+/// the fallback access point is already provisioned while station mode is
+/// attempted; on repeated failure it drops to AP-only. `EspSystemEventLoop`
+/// WiFi/IP event subscriptions update the link state, and once connected
+/// this loop replaces the old `thread::park()`: it checks the link state
+/// once a second and reconnects with backoff after a disconnect rather than
+/// leaving the radio silently offline.
+fn run_wifi_link_state_machine(
+	modem: Modem,
+	server_state: Arc<RwLock<Arc<FFTData>>>,
+	tunable_config: Arc<RwLock<TunableConfig>>,
+	nvs_partition: EspDefaultNvsPartition,
+) -> Result<()> {
+	if STA_SSID.is_empty() {
+		info!("STA_SSID is empty, skipping station mode and starting the access point directly");
+		let _wifi = init_wifi_ap(modem)?;
+		let _server = init_http_server(server_state.clone(), tunable_config, nvs_partition)?;
+
+		let mut current_power_mode = PowerSaveMode::NoPowerSave;
+		set_power_save_mode(current_power_mode);
+		loop {
+			apply_idle_power_save(&server_state, &mut current_power_mode);
+			thread::sleep(Duration::from_secs(1));
+		}
+	}
+
+	let (nvs, sysloop) = init_wifi_module()?;
+
+	let mut wifi = BlockingWifi::wrap(
+		EspWifi::new(modem, sysloop.clone(), Some(nvs)).unwrap(),
+		sysloop.clone(),
+	)
+	.expect("Failed to initialise WiFi");
+
+	wifi.set_configuration(&WifiConfiguration::Mixed(
+		ClientConfiguration {
+			ssid: STA_SSID.try_into().unwrap(),
+			password: STA_PASSWORD.try_into().unwrap(),
+			auth_method: AuthMethod::WPA2Personal,
+			..Default::default()
+		},
+		ap_configuration(),
+	))
+	.expect("Failed to set WiFi configuration");
+
+	wifi.start().expect("Failed to start WiFi");
+
+	let link_state = Arc::new(RwLock::new(WifiLinkState::Idle));
+
+	let wifi_link_state = link_state.clone();
+	let _wifi_event_sub = sysloop
+		.subscribe::<WifiEvent, _>(move |event| match event {
+			WifiEvent::StaConnected => {
+				*wifi_link_state.write().unwrap() = WifiLinkState::Connecting;
+			}
+			WifiEvent::StaDisconnected => {
+				// Once we've permanently fallen back to AP-only, station
+				// mode is no longer configured, so stray disconnect events
+				// shouldn't pull the state back into the retry path.
+				let mut state = wifi_link_state.write().unwrap();
+				if *state != WifiLinkState::ApFallback {
+					*state = WifiLinkState::Lost;
+				}
+			}
+			_ => {}
+		})
+		.expect("Failed to subscribe to WiFi events");
+
+	let ip_link_state = link_state.clone();
+	let _ip_event_sub = sysloop
+		.subscribe::<IpEvent, _>(move |event| {
+			if matches!(event, IpEvent::DhcpIpAssigned(_)) {
+				*ip_link_state.write().unwrap() = WifiLinkState::Connected;
+			}
+		})
+		.expect("Failed to subscribe to IP events");
+
+	if connect_station_with_retry(&mut wifi, &link_state) {
+		info!("Connected to `{}` in station mode", STA_SSID);
+	} else {
+		warn!(
+			"Station mode failed after {} attempts, falling back to the access point",
+			WIFI_STA_MAX_RETRIES
+		);
+		wifi.set_configuration(&WifiConfiguration::AccessPoint(ap_configuration()))
+			.expect("Failed to set WiFi configuration");
+		info!(
+			"WiFi Access Point started: SSID=`{}`, Password=`{}`",
+			WIFI_SSID, WIFI_PASSWORD
+		);
+		*link_state.write().unwrap() = WifiLinkState::ApFallback;
+	}
+
+	let _server = init_http_server(server_state.clone(), tunable_config, nvs_partition)?;
+
+	let mut current_power_mode = PowerSaveMode::NoPowerSave;
+	set_power_save_mode(current_power_mode);
+
+	loop {
+		if *link_state.read().unwrap() == WifiLinkState::Lost {
+			warn!("Station link lost, attempting to reconnect...");
+			if !connect_station_with_retry(&mut wifi, &link_state) {
+				warn!("Reconnect attempts exhausted, falling back to the access point");
+				wifi.set_configuration(&WifiConfiguration::AccessPoint(ap_configuration()))
+					.expect("Failed to set WiFi configuration");
+				*link_state.write().unwrap() = WifiLinkState::ApFallback;
+			}
+		}
+
+		apply_idle_power_save(&server_state, &mut current_power_mode);
+
+		thread::sleep(Duration::from_secs(1));
+	}
+}
+
+/// Build the `<option>` list for the training-label `<select>`, including
+/// the "none" sentinel that leaves training mode a no-op.
+fn render_training_label_options(current: u8) -> String {
+	let mut options = format!(
+		r#"<option value="{}"{}>(none - training mode off)</option>"#,
+		NO_TRAINING_LABEL,
+		if current == NO_TRAINING_LABEL { " selected" } else { "" }
+	);
+
+	for (label_index, label) in COCONUT_LABELS.iter().enumerate() {
+		options.push_str(&format!(
+			r#"<option value="{}"{}>{}</option>"#,
+			label_index,
+			if current as usize == label_index { " selected" } else { "" },
+			label
+		));
+	}
+
+	options
+}
+
+/// Render the live tuning values as a plain HTML form. Keeps the same
+/// "inline `include_str!` for the static page, `format!` for anything
+/// data-driven" split the rest of the server uses.
+fn render_config_form(config: &TunableConfig) -> String {
+	format!(
+		r#"<!DOCTYPE html>
+<html>
+<head><title>FFT Analyzer Config</title></head>
+<body>
+<h1>Tuning parameters</h1>
+<form method="POST" action="/config">
+<label>Low-frequency amplitude threshold <input type="number" step="any" name="low_freq_threshold" value="{low_freq_threshold}"></label><br>
+<label>High-frequency amplitude threshold <input type="number" step="any" name="high_freq_threshold" value="{high_freq_threshold}"></label><br>
+<label>Amplitude frequency cutoff (Hz) <input type="number" step="any" name="frequency_cutoff" value="{frequency_cutoff}"></label><br>
+<label>Onset flux multiplier (k) <input type="number" step="any" name="onset_flux_multiplier" value="{onset_flux_multiplier}"></label><br>
+<label>Impulse time threshold (ms) <input type="number" name="impulse_time_threshold" value="{impulse_time_threshold}"></label><br>
+<label>Classifier distance cutoff <input type="number" step="any" name="classifier_cutoff" value="{classifier_cutoff}"></label><br>
+<label>Spectral EMA alpha (overlapping-window smoothing) <input type="number" step="any" min="0" max="1" name="spectral_ema_alpha" value="{spectral_ema_alpha}"></label><br>
+<label>Training label (selected while training mode is on, toggled with a long button press) <select name="training_label">{training_label_options}</select></label><br>
+<button type="submit">Save</button>
+</form>
+</body>
+</html>"#,
+		low_freq_threshold = config.amplitude_threshold.low_freq_threshold,
+		high_freq_threshold = config.amplitude_threshold.high_freq_threshold,
+		frequency_cutoff = config.amplitude_threshold.frequency_cutoff,
+		onset_flux_multiplier = config.onset_flux_multiplier,
+		impulse_time_threshold = config.impulse_time_threshold,
+		classifier_cutoff = config.classifier_cutoff,
+		spectral_ema_alpha = config.spectral_ema_alpha,
+		training_label_options = render_training_label_options(config.training_label_index),
+	)
+}
+
+/// Parse the `application/x-www-form-urlencoded` body posted by the tuning
+/// form, overlaying any recognised fields on top of `current` so a partial
+/// or malformed submission can't zero out the rest of the config.
+fn apply_config_form(current: &TunableConfig, body: &str) -> TunableConfig {
+	let mut config = *current;
+
+	for pair in body.split('&') {
+		let Some((key, value)) = pair.split_once('=') else {
+			continue;
+		};
+		let Ok(value) = value.parse::<f32>() else {
+			continue;
+		};
+
+		match key {
+			"low_freq_threshold" => config.amplitude_threshold.low_freq_threshold = value,
+			"high_freq_threshold" => config.amplitude_threshold.high_freq_threshold = value,
+			"frequency_cutoff" => config.amplitude_threshold.frequency_cutoff = value,
+			"onset_flux_multiplier" => config.onset_flux_multiplier = value,
+			"impulse_time_threshold" => config.impulse_time_threshold = value as u64,
+			"classifier_cutoff" => config.classifier_cutoff = value,
+			"spectral_ema_alpha" => config.spectral_ema_alpha = value.clamp(0.0, 1.0),
+			"training_label" => {
+				let label_index = value as u8;
+				if label_index == NO_TRAINING_LABEL || (label_index as usize) < COCONUT_LABELS.len() {
+					config.training_label_index = label_index;
+				}
+			}
+			_ => {}
+		}
+	}
+
+	config
+}
+
+pub fn init_http_server(
+	server_state: Arc<RwLock<Arc<FFTData>>>,
+	tunable_config: Arc<RwLock<TunableConfig>>,
+	nvs_partition: EspDefaultNvsPartition,
+) -> Result<EspHttpServer<'static>> {
 	info!("Starting HTTP server...");
 	let mut server =
 		EspHttpServer::new(&Configuration::default()).expect("Failed to create HTTP server");
@@ -76,9 +411,67 @@ pub fn init_http_server(server_state: Arc<RwLock<Arc<FFTData>>>) -> Result<EspHt
 		})
 		.expect("Failed to register index handler");
 
+	// Serve the tuning form, pre-filled with the live values
+	let config_form_state = tunable_config.clone();
+	server
+		.fn_handler("/config", esp_idf_svc::http::Method::Get, move |request| {
+			let html = render_config_form(&config_form_state.read().unwrap());
+			let mut resp = request.into_ok_response()?;
+			resp.write(html.as_bytes())?;
+			Ok::<(), EspIOError>(())
+		})
+		.expect("Failed to register config form handler");
+
+	// Accept updated tuning values and persist them to NVS
+	let config_post_state = tunable_config.clone();
+	server
+		.fn_handler("/config", esp_idf_svc::http::Method::Post, move |mut request| {
+			// The form only ever posts a handful of short numeric fields, so
+			// cap how much of the body we'll buffer; this is LAN-exposed
+			// (and, before station mode connects, reachable from the
+			// fallback AP) on RAM-constrained hardware, so an
+			// oversized or slow-trickling POST shouldn't be able to grow
+			// this buffer unbounded.
+			let mut body = Vec::new();
+			let mut chunk = [0u8; 256];
+			let mut truncated = false;
+			loop {
+				if body.len() >= MAX_CONFIG_BODY_BYTES {
+					truncated = true;
+					break;
+				}
+				match request.read(&mut chunk) {
+					Ok(0) => break,
+					Ok(n) => body.extend_from_slice(&chunk[..n]),
+					Err(_) => break,
+				}
+			}
+			if truncated {
+				warn!("Config POST body exceeded {} bytes, truncating", MAX_CONFIG_BODY_BYTES);
+				// Keep draining (without buffering) past the cap so the rest
+				// of this request's body doesn't get left on the connection
+				// and misread as the start of the next request.
+				while !matches!(request.read(&mut chunk), Ok(0) | Err(_)) {}
+			}
+			let body = String::from_utf8_lossy(&body);
+
+			let updated = apply_config_form(&config_post_state.read().unwrap(), &body);
+			*config_post_state.write().unwrap() = updated;
+
+			if let Err(e) = save_config(nvs_partition.clone(), &updated) {
+				warn!("Failed to persist config to NVS: {:?}", e);
+			}
+
+			let mut resp = request.into_ok_response()?;
+			resp.write(render_config_form(&updated).as_bytes())?;
+			Ok::<(), EspIOError>(())
+		})
+		.expect("Failed to register config update handler");
+
 	// WebSocket endpoint
 	server.ws_handler("/ws", move |ws: &mut EspHttpWsConnection| {
 		let mut last_impulse_timestamp = None;
+		WS_CLIENT_COUNT.fetch_add(1, Ordering::Relaxed);
 		loop {
 			// Access the latest FFT data
 			let current_data = {
@@ -150,6 +543,7 @@ pub fn init_http_server(server_state: Arc<RwLock<Arc<FFTData>>>) -> Result<EspHt
 			// Control update rate (~60 Hz)
 			std::thread::sleep(Duration::from_millis(AUDIO_SAMPLE_PER_SECOND / 4));
 		}
+		WS_CLIENT_COUNT.fetch_sub(1, Ordering::Relaxed);
 		Ok::<(), EspIOError>(())
 	})?;
 
@@ -157,7 +551,12 @@ pub fn init_http_server(server_state: Arc<RwLock<Arc<FFTData>>>) -> Result<EspHt
 	Ok(server)
 }
 
-pub fn spawn_wifi_thread(modem: Modem, server_state: Arc<RwLock<Arc<FFTData>>>) -> Result<()> {
+pub fn spawn_wifi_thread(
+	modem: Modem,
+	server_state: Arc<RwLock<Arc<FFTData>>>,
+	tunable_config: Arc<RwLock<TunableConfig>>,
+	nvs_partition: EspDefaultNvsPartition,
+) -> Result<()> {
 	let config = ThreadSpawnConfiguration {
 		name: Some(b"Wifi Thread\0"),
 		priority: 5,
@@ -175,13 +574,58 @@ pub fn spawn_wifi_thread(modem: Modem, server_state: Arc<RwLock<Arc<FFTData>>>)
 				"WiFi server thread running on core: {:#?}",
 				esp_idf_svc::hal::cpu::core()
 			);
-			let _wifi = init_wifi_ap(modem).expect("Failed to initialise Wi-Fi Access Point.");
 
-			let _server =
-				init_http_server(server_state).expect("Failed to initialise HTTP server.");
-
-			thread::park();
+			run_wifi_link_state_machine(modem, server_state, tunable_config, nvs_partition)
+				.expect("WiFi link-state machine exited unexpectedly");
 		})
 		.expect("Failed to spawn WiFi/server thread!");
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn apply_config_form_overlays_recognised_fields() {
+		let current = TunableConfig::default();
+		let updated = apply_config_form(&current, "classifier_cutoff=0.3&spectral_ema_alpha=0.6");
+
+		assert_eq!(updated.classifier_cutoff, 0.3);
+		assert_eq!(updated.spectral_ema_alpha, 0.6);
+		// Unmentioned fields fall back to `current`, not zeroed out.
+		assert_eq!(updated.onset_flux_multiplier, current.onset_flux_multiplier);
+	}
+
+	#[test]
+	fn apply_config_form_ignores_malformed_pairs() {
+		let current = TunableConfig::default();
+		let updated = apply_config_form(&current, "classifier_cutoff=not_a_number&garbage");
+
+		assert_eq!(updated.classifier_cutoff, current.classifier_cutoff);
+	}
+
+	#[test]
+	fn apply_config_form_accepts_in_range_training_label() {
+		let current = TunableConfig::default();
+		let updated = apply_config_form(&current, "training_label=1");
+
+		assert_eq!(updated.training_label_index, 1);
+	}
+
+	#[test]
+	fn apply_config_form_accepts_no_training_label_sentinel() {
+		let current = TunableConfig::default();
+		let updated = apply_config_form(&current, "training_label=255");
+
+		assert_eq!(updated.training_label_index, NO_TRAINING_LABEL);
+	}
+
+	#[test]
+	fn apply_config_form_rejects_out_of_range_training_label() {
+		let current = TunableConfig::default();
+		let updated = apply_config_form(&current, "training_label=200");
+
+		assert_eq!(updated.training_label_index, current.training_label_index);
+	}
+}